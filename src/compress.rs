@@ -0,0 +1,105 @@
+//! negotiating and applying response compression.
+
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder};
+use tokio::io::AsyncWriteExt;
+
+/// minimum body size, in bytes, before we bother compressing a response.
+pub const THRESHOLD: usize = 1024;
+
+/// a content-coding we know how to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// the value this encoding is advertised as in `Content-Encoding`.
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// the file extension used for precompressed sidecar artifacts, e.g.
+    /// `foo.css.br`.
+    fn sidecar_extension(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// whether `accept_encoding` lists `coding` as acceptable: present with no
+/// `q` parameter, or with a `q` parameter greater than zero. `q=0` is an
+/// explicit "never use this", per RFC 7231 §5.3.1. a `*` entry matches any
+/// coding not explicitly listed, per RFC 7231 §5.3.4 — an explicit entry for
+/// `coding` always takes precedence over `*`, whichever order they appear in.
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut explicit = None;
+    let mut wildcard = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q=")?.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            explicit = Some(q);
+        } else if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    explicit.or(wildcard).map_or(false, |q| q > 0.0)
+}
+
+/// picks the best encoding the client advertised via `Accept-Encoding`,
+/// preferring brotli over gzip when both are offered. respects `;q=0` as
+/// "not acceptable" instead of treating the coding's mere presence in the
+/// header as consent to use it.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+    if accepts(accept_encoding, "br") {
+        Some(Encoding::Brotli)
+    } else if accepts(accept_encoding, "gzip") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// compresses `data` with the given encoding.
+pub async fn compress(data: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzipEncoder::new(Vec::new());
+            encoder.write_all(data).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        },
+        Encoding::Brotli => {
+            let mut encoder = BrotliEncoder::new(Vec::new());
+            encoder.write_all(data).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        },
+    }
+}
+
+/// looks for a precompressed sibling artifact for `path` (e.g. `foo.css.br`
+/// next to `foo.css`), returning it only if it actually exists on disk.
+pub async fn precompressed_sibling(path: &Path, encoding: Encoding) -> Option<PathBuf> {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.sidecar_extension());
+    let sibling = PathBuf::from(sibling);
+
+    tokio::fs::metadata(&sibling).await.ok().map(|_| sibling)
+}
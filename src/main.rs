@@ -1,8 +1,11 @@
+mod compress;
+
 use comrak::{
     nodes::{AstNode, NodeCodeBlock, NodeHtmlBlock, NodeValue},
     Arena,
     ComrakExtensionOptions,
     ComrakOptions,
+    ComrakParseOptions,
     ComrakRenderOptions,
 };
 use crossterm::{
@@ -10,6 +13,7 @@ use crossterm::{
     style::{Colorize, Print, PrintStyledContent},
 };
 use std::{
+    collections::HashMap,
     ffi::OsStr,
     io::stdout,
     net::SocketAddr,
@@ -17,24 +21,30 @@ use std::{
     sync::Arc,
     time::Instant,
 };
+use once_cell::sync::Lazy;
 use structopt::StructOpt;
 use syntect::{
-    dumps,
     highlighting::ThemeSet,
-    html::highlighted_html_for_string,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
     parsing::SyntaxSet,
+    util::LinesWithEndings,
 };
 use tera::{Context, Tera};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio_util::io::ReaderStream;
 use warp::{
+    http::{Response, StatusCode},
+    hyper::Body,
     path::FullPath,
     reject::{self, Reject},
     reply,
-    reply::Html,
     Filter,
     Rejection,
     Reply,
 };
 
+use compress::Encoding;
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Serve your files over http!")]
 struct Opt {
@@ -48,6 +58,25 @@ struct Opt {
 
     #[structopt(help = "The directory to serve", default_value = ".")]
     dir: PathBuf,
+
+    #[structopt(
+        long,
+        help = "The default syntax highlighting theme to use",
+        default_value = "base16-ocean.dark"
+    )]
+    theme: String,
+
+    #[structopt(
+        long,
+        help = "Convert straight quotes/dashes in markdown to their typographic forms"
+    )]
+    smart: bool,
+
+    #[structopt(long, help = "Replace :emoji: shortcodes in markdown with their emoji")]
+    emoji: bool,
+
+    #[structopt(long, help = "Minify rendered HTML before sending it to the client")]
+    minify: bool,
 }
 
 #[tokio::main]
@@ -87,42 +116,104 @@ async fn main() -> anyhow::Result<()> {
         ),
     ])?;
 
-    // previously binary dumped version of the dracula theme for performance
-    let theme_set = dumps::from_binary(include_bytes!("../assets/Dracula.themedump"));
+    // syntect's bundled themes (base16 variants, Solarized, InspiredGitHub,
+    // ...), so there's an actual choice of themes to switch between, not
+    // just the one the binary used to ship with.
+    let theme_set: ThemeSet = ThemeSet::load_defaults();
+
+    // precompute a stylesheet for every theme we know about, so switching
+    // themes is just a matter of linking a different `/_ultiserve/theme/*.css`
+    // instead of re-rendering every highlighted code block.
+    let theme_css = theme_set
+        .themes
+        .iter()
+        .filter_map(|(name, theme)| {
+            css_for_theme_with_class_style(theme, ClassStyle::SpacedPrefixed)
+                .ok()
+                .map(|css| (name.clone(), css))
+        })
+        .collect();
+
     let tools = Arc::new(Tools {
         tera,
         syntax_set: SyntaxSet::load_defaults_newlines(),
-        theme_set,
+        theme_css,
         opt,
     });
 
     let addr = tools.opt.addr;
-    warp::serve(
-        warp::path::full()
-            .and(warp::query::<GetParams>())
-            .and_then(move |path, get_params| on_get_timed(path, get_params, Arc::clone(&tools))),
-    )
-    .run(addr)
-    .await;
+    let tools_for_get = Arc::clone(&tools);
+    let tools_for_css = Arc::clone(&tools);
+
+    let get_route = warp::path::full()
+        .and(warp::query::<GetParams>())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(move |path, get_params, range, accept_encoding, host| {
+            on_get_timed(
+                path,
+                get_params,
+                range,
+                accept_encoding,
+                host,
+                Arc::clone(&tools_for_get),
+            )
+        });
+
+    let theme_css_route = warp::path!("_ultiserve" / "theme" / String)
+        .and_then(move |name: String| serve_theme_css(name, Arc::clone(&tools_for_css)));
+
+    warp::serve(theme_css_route.or(get_route)).run(addr).await;
 
     Ok(())
 }
 
+/// serves the precomputed stylesheet for a syntax highlighting theme, e.g.
+/// `/_ultiserve/theme/base16-ocean.dark.css`.
+async fn serve_theme_css(name: String, tools: Arc<Tools>) -> Result<Box<dyn Reply>, Rejection> {
+    let theme_name = name.strip_suffix(".css").unwrap_or(&name);
+    match tools.theme_css.get(theme_name) {
+        Some(css) => {
+            let response = Response::builder()
+                .header("Content-Type", "text/css")
+                .body(Body::from(css.clone()))
+                .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+            Ok(Box::new(response))
+        },
+        None => Err(reject::not_found()),
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct GetParams {
     #[serde(default)]
     raw: bool,
+    /// overrides the server's default syntax highlighting theme for this
+    /// request
+    theme: Option<String>,
 }
 
 // calls on_get and prints the time needed to execute it.
 async fn on_get_timed(
     full_path: FullPath,
     get_params: GetParams,
+    range: Option<String>,
+    accept_encoding: Option<String>,
+    host: Option<String>,
     tools: Arc<Tools>,
 ) -> Result<Box<dyn Reply>, Rejection> {
     let path_str = full_path.as_str().to_string();
     let start_time = Instant::now();
-    let reply = on_get(full_path, get_params, Arc::clone(&tools)).await;
+    let reply = on_get(
+        full_path,
+        get_params,
+        range,
+        accept_encoding,
+        host,
+        Arc::clone(&tools),
+    )
+    .await;
     let time_needed = start_time.elapsed();
     println!(
         "Processed request to {} in {}{}",
@@ -138,6 +229,9 @@ async fn on_get_timed(
 async fn on_get(
     full_path: FullPath,
     get_params: GetParams,
+    range: Option<String>,
+    accept_encoding: Option<String>,
+    host: Option<String>,
     tools: Arc<Tools>,
 ) -> Result<Box<dyn Reply>, Rejection> {
     let full_path = full_path.as_str();
@@ -145,6 +239,12 @@ async fn on_get(
     let mut path = tools.opt.dir.clone();
     // we don't want to go to root, so we remove the / at the start
     path.push(full_path.trim_start_matches('/'));
+    // the theme the client asked for, falling back to the server's default
+    let theme = get_params
+        .theme
+        .clone()
+        .unwrap_or_else(|| tools.opt.theme.clone());
+    let encoding = compress::negotiate(accept_encoding.as_deref());
     match tokio::fs::read_dir(&path).await {
         // if we have a dir render index page
         Ok(mut dir) => {
@@ -162,14 +262,22 @@ async fn on_get(
                     is_dir = true;
                 }
 
-                let entry = FileEntry { name, is_dir };
+                let kind = FileKind::of(&name, is_dir);
+                let entry = FileEntry {
+                    name,
+                    is_dir,
+                    kind,
+                };
 
                 files.push(entry);
             }
 
-            // sort by name
-            // TODO use proper alphabetical sorting
-            files.sort_by(|a, b| a.name.cmp(&b.name));
+            // directories first, then natural ("human") order within each group
+            files.sort_by(|a, b| {
+                b.is_dir
+                    .cmp(&a.is_dir)
+                    .then_with(|| natural_cmp(&a.name, &b.name))
+            });
 
             let content = IndexContent {
                 files,
@@ -181,12 +289,13 @@ async fn on_get(
                     .unwrap_or_else(|_| "<unknown>".to_string()),
                 current_dir: full_path.trim_end_matches('/').to_string(),
                 has_parent: full_path != "/",
+                theme,
             };
 
             if let Ok(rendered) =
                 Context::from_serialize(content).and_then(|c| tools.tera.render("index.html", &c))
             {
-                Ok(Box::new(reply::html(rendered)))
+                html_reply(&tools, rendered, encoding).await
             } else {
                 // TODO implement reject handlers
                 Err(reject::custom(UltiserveReject::RenderFail))
@@ -194,25 +303,30 @@ async fn on_get(
         },
         // if there's no dir use the file template
         _ => {
-            if let Ok(bytes) = tokio::fs::read(&path).await {
+            if get_params.raw {
+                // the raw path reads the file lazily: a `Range` request only
+                // ever needs to seek a slice of it, and a full request is
+                // streamed from a file handle unless it gets compressed on
+                // the fly.
+                serve_raw_bytes(&path, range, encoding).await
+            } else if let Ok(bytes) = tokio::fs::read(&path).await {
                 // check if we have valid utf8
-                match String::from_utf8(bytes.clone()) {
+                match String::from_utf8(bytes) {
                     // if the file is valid utf8, render the file template
                     Ok(file_content) => {
-                        if get_params.raw {
-                            Ok(Box::new(file_content))
-                        } else {
-                            render_file_to_reply(
-                                tools,
-                                &path,
-                                file_content,
-                                full_path.trim_end_matches("/"),
-                            )
-                            .map(|r| Box::new(r) as Box<dyn Reply>)
-                        }
+                        render_file_to_reply(
+                            tools,
+                            &path,
+                            file_content,
+                            full_path.trim_end_matches("/"),
+                            theme,
+                            encoding,
+                            host.as_deref(),
+                        )
+                        .await
                     },
                     // if the file is not utf8, give the client the raw bytes
-                    _ => Ok(Box::new(bytes)),
+                    _ => serve_raw_bytes(&path, range, encoding).await,
                 }
             } else {
                 // if there is no file, 404
@@ -222,20 +336,299 @@ async fn on_get(
     }
 }
 
+/// serves the raw bytes of a file, honoring an optional `Range` header so
+/// large files (e.g. videos) can be seeked and resumed. a `Range` request
+/// always gets the identity encoding, since compression would shift the
+/// byte offsets the client asked for.
+///
+/// a `Range` request only ever needs the file's length (to validate the
+/// range) and the slice it asks for, so this never buffers the whole file:
+/// the length comes from `metadata`, and the slice is read directly off a
+/// freshly opened file handle. a full (non-`Range`) identity response is
+/// streamed straight from an open file handle too; only the on-the-fly
+/// compression path in `serve_full_bytes` needs the body in memory.
+async fn serve_raw_bytes(
+    path: &Path,
+    range: Option<String>,
+    encoding: Option<Encoding>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let content_type = guess_content_type(path);
+    let total = tokio::fs::metadata(path)
+        .await
+        .map_err(|_| reject::custom(UltiserveReject::RenderFail))?
+        .len();
+
+    if let Some(range) = range.as_deref() {
+        return match parse_range(range, total) {
+            Some(ParsedRange::Satisfiable { start, end }) => {
+                let mut file = tokio::fs::File::open(path)
+                    .await
+                    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+                let len = end - start + 1;
+                let mut buf = vec![0u8; len as usize];
+                file.read_exact(&mut buf)
+                    .await
+                    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+                let response = Response::builder()
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("Content-Type", content_type)
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, total))
+                    .header("Content-Length", len)
+                    .header("Accept-Ranges", "bytes")
+                    .body(Body::from(buf))
+                    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+                Ok(Box::new(response))
+            },
+            Some(ParsedRange::Unsatisfiable) => {
+                let response = Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header("Content-Type", content_type)
+                    .header("Content-Range", format!("bytes */{}", total))
+                    .body(Body::empty())
+                    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+                Ok(Box::new(response))
+            },
+            // not a `Range` header we understand, fall back to a full response
+            None => serve_full_bytes(path, total, content_type, None).await,
+        };
+    }
+
+    serve_full_bytes(path, total, content_type, encoding).await
+}
+
+/// serves the entirety of a file, still advertising range support. prefers a
+/// precompressed sibling artifact on disk over compressing on the fly, and
+/// streams both that sibling and an uncompressed identity response straight
+/// off an open file handle rather than buffering them. only the on-the-fly
+/// compression path needs to hold the file in memory, since `compress::compress`
+/// has no streaming variant.
+async fn serve_full_bytes(
+    path: &Path,
+    total: u64,
+    content_type: &str,
+    encoding: Option<Encoding>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Some(encoding) = encoding {
+        if let Some(sibling) = compress::precompressed_sibling(path, encoding).await {
+            if let Ok(file) = tokio::fs::File::open(&sibling).await {
+                let len = file.metadata().await.ok().map(|m| m.len());
+                return encoded_stream_response(file, len, content_type, encoding);
+            }
+        }
+
+        if total > compress::THRESHOLD as u64 {
+            let bytes = tokio::fs::read(path)
+                .await
+                .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+            if let Ok(compressed) = compress::compress(&bytes, encoding).await {
+                return encoded_response(compressed, content_type, encoding);
+            }
+        }
+    }
+
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+    let response = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Length", total)
+        .header("Accept-Ranges", "bytes")
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+    Ok(Box::new(response))
+}
+
+/// builds a reply out of an already-compressed, fully buffered body (the
+/// on-the-fly compression path, which has no streaming variant).
+fn encoded_response(
+    body: Vec<u8>,
+    content_type: &str,
+    encoding: Encoding,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let response = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len())
+        .header("Content-Encoding", encoding.header_value())
+        .header("Vary", "Accept-Encoding")
+        .header("Accept-Ranges", "bytes")
+        .body(Body::from(body))
+        .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+    Ok(Box::new(response))
+}
+
+/// builds a reply by streaming an already-compressed file (a precompressed
+/// sidecar artifact) straight off an open file handle.
+fn encoded_stream_response(
+    file: tokio::fs::File,
+    content_length: Option<u64>,
+    content_type: &str,
+    encoding: Encoding,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let mut builder = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Encoding", encoding.header_value())
+        .header("Vary", "Accept-Encoding")
+        .header("Accept-Ranges", "bytes");
+
+    if let Some(len) = content_length {
+        builder = builder.header("Content-Length", len);
+    }
+
+    let response = builder
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+    Ok(Box::new(response))
+}
+
+/// guesses a `Content-Type` for a file from its extension, falling back to
+/// `application/octet-stream` for anything we don't recognize. this is only
+/// used for raw byte responses; rendered html/css replies set their own
+/// content type.
+fn guess_content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md" | "markdown") => "text/markdown; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mkv") => "video/x-matroska",
+        Some("mov") => "video/quicktime",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("opus") => "audio/opus",
+        Some("m4a") => "audio/mp4",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// renders an html reply: minifying and compressing it as configured. this
+/// is the single choke point where HTML replies are built.
+async fn html_reply(
+    tools: &Tools,
+    html: String,
+    encoding: Option<Encoding>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let html = if tools.opt.minify {
+        minify_html(&html)
+    } else {
+        html
+    };
+
+    if let Some(encoding) = encoding.filter(|_| html.len() > compress::THRESHOLD) {
+        if let Ok(compressed) = compress::compress(html.as_bytes(), encoding).await {
+            let response = Response::builder()
+                .header("Content-Type", "text/html; charset=utf-8")
+                .header("Content-Length", compressed.len())
+                .header("Content-Encoding", encoding.header_value())
+                .header("Vary", "Accept-Encoding")
+                .body(Body::from(compressed))
+                .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+            return Ok(Box::new(response));
+        }
+    }
+
+    Ok(Box::new(reply::html(html)))
+}
+
+enum ParsedRange {
+    Satisfiable { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+static RANGE_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"^bytes=(\d*)-(\d*)$").unwrap());
+
+/// parses a `Range: bytes=start-end` header, clamping both ends to the
+/// length of the file being served.
+fn parse_range(range: &str, total_len: u64) -> Option<ParsedRange> {
+    let caps = RANGE_RE.captures(range)?;
+    let start = caps.get(1).map_or("", |m| m.as_str());
+    let end = caps.get(2).map_or("", |m| m.as_str());
+
+    let (start, end) = match (start, end) {
+        // no bounds at all isn't a valid range
+        ("", "") => return None,
+        // `bytes=-N` means the last N bytes
+        ("", end) => {
+            let n: u64 = end.parse().ok()?;
+            let n = n.min(total_len);
+            (total_len.saturating_sub(n), total_len.saturating_sub(1))
+        },
+        // `bytes=N-` means from N to EOF
+        (start, "") => (start.parse().ok()?, total_len.saturating_sub(1)),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(total_len.saturating_sub(1)),
+        ),
+    };
+
+    if start >= total_len || start > end {
+        Some(ParsedRange::Unsatisfiable)
+    } else {
+        Some(ParsedRange::Satisfiable { start, end })
+    }
+}
+
 /// renders a file using the file template, and turns it into a reply.
-fn render_file_to_reply(
+async fn render_file_to_reply(
     tools: Arc<Tools>,
     path: &Path,
     mut content: String,
     url: &str,
-) -> Result<Html<String>, Rejection> {
+    theme: String,
+    encoding: Option<Encoding>,
+    host: Option<&str>,
+) -> Result<Box<dyn Reply>, Rejection> {
     let file_ext = path.extension().and_then(OsStr::to_str);
     match file_ext {
         // don't put html files into the file template, just send them raw.
-        Some("html") | Some("html5") => Ok(reply::html(content)),
+        Some("html") | Some("html5") => html_reply(&tools, content, encoding).await,
         // render markdown
         Some("md") | Some("markdown") => {
-            render_markdown_to_reply(Arc::clone(&tools), path, &content, url)
+            render_markdown_to_reply(
+                Arc::clone(&tools),
+                path,
+                &content,
+                url,
+                theme,
+                encoding,
+                host,
+            )
+            .await
         },
         ext => {
             let mut unsafe_content = false;
@@ -247,27 +640,41 @@ fn render_file_to_reply(
                 unsafe_content = true;
             }
 
-            create_file_reply(tools, path, content, unsafe_content, url)
+            create_file_reply(tools, path, content, unsafe_content, url, theme, encoding).await
         },
     }
 }
 
 /// highlights the given string with syntax for the given file extension if it
-/// exists, and renders it as html.
+/// exists, and renders it as classed (theme-independent) html. the actual
+/// colors come from whichever `/_ultiserve/theme/*.css` stylesheet is linked.
 fn syntax_highlight_html(tools: Arc<Tools>, file_ext: &str, content: &str) -> Option<String> {
-    tools.syntax_set.find_syntax_by_token(file_ext).map(|s| {
-        let theme = &tools.theme_set.themes["Dracula"];
-        highlighted_html_for_string(content, &tools.syntax_set, s, theme)
+    tools.syntax_set.find_syntax_by_token(file_ext).map(|syntax| {
+        let mut generator = ClassedHTMLGenerator::new_with_class_style(
+            syntax,
+            &tools.syntax_set,
+            ClassStyle::SpacedPrefixed,
+        );
+        for line in LinesWithEndings::from(content) {
+            // the classed generator only fails on internal syntect bugs, not
+            // on malformed input, so there's nothing useful to do with an err
+            let _ = generator.parse_html_for_line_which_includes_newline(line);
+        }
+
+        format!("<pre class=\"code\">\n{}</pre>\n", generator.finalize())
     })
 }
 
 /// renders a markdown file to a http reply
-fn render_markdown_to_reply(
+async fn render_markdown_to_reply(
     tools: Arc<Tools>,
     path: &Path,
     content: &str,
     url: &str,
-) -> Result<Html<String>, Rejection> {
+    theme: String,
+    encoding: Option<Encoding>,
+    host: Option<&str>,
+) -> Result<Box<dyn Reply>, Rejection> {
     let arena = Arena::new();
     let options = ComrakOptions {
         extension: ComrakExtensionOptions {
@@ -276,6 +683,7 @@ fn render_markdown_to_reply(
             autolink: true,
             tasklist: true,
             header_ids: Some("user-content-".to_string()),
+            shortcodes: tools.opt.emoji,
             ..Default::default()
         },
         render: ComrakRenderOptions {
@@ -283,7 +691,10 @@ fn render_markdown_to_reply(
             unsafe_: true,
             ..Default::default()
         },
-        parse: Default::default(),
+        parse: ComrakParseOptions {
+            smart: tools.opt.smart,
+            ..Default::default()
+        },
     };
 
     let document = comrak::parse_document(&arena, content, &options);
@@ -301,25 +712,28 @@ fn render_markdown_to_reply(
 
     iter_nodes(document, &|node| {
         let mut new_val = None;
-        if let NodeValue::CodeBlock(NodeCodeBlock {
-            fenced: true,
-            info,
-            literal,
-            ..
-        }) = &node.data.borrow().value
-        {
-            // only continue if info and content are valid utf8
-            if let (Ok(info), Ok(literal)) = (
-                // clone required to allocate string
-                String::from_utf8(info.clone()),
-                String::from_utf8(literal.clone()),
-            ) {
-                if let Some(html) = syntax_highlight_html(Arc::clone(&tools), &info, &literal) {
-                    let mut html_block = NodeHtmlBlock::default();
-                    html_block.literal = html.into();
-                    new_val = Some(NodeValue::HtmlBlock(html_block));
+        match &node.data.borrow().value {
+            NodeValue::CodeBlock(NodeCodeBlock {
+                fenced: true,
+                info,
+                literal,
+                ..
+            }) => {
+                // only continue if info and content are valid utf8
+                if let (Ok(info), Ok(literal)) = (
+                    // clone required to allocate string
+                    String::from_utf8(info.clone()),
+                    String::from_utf8(literal.clone()),
+                ) {
+                    if let Some(html) = syntax_highlight_html(Arc::clone(&tools), &info, &literal)
+                    {
+                        let mut html_block = NodeHtmlBlock::default();
+                        html_block.literal = html.into();
+                        new_val = Some(NodeValue::HtmlBlock(html_block));
+                    }
                 }
-            }
+            },
+            _ => {},
         }
 
         // we have to do this here, so node isn't borrowed while we swap the value
@@ -332,19 +746,167 @@ fn render_markdown_to_reply(
     comrak::format_html(document, &options, &mut html)
         .map_err(|_| UltiserveReject::MarkdownFail)?;
     let html = String::from_utf8(html).map_err(|_| UltiserveReject::MarkdownFail)?;
+    let html = harden_external_links(&html, host);
+
+    create_file_reply(tools, path, html, true, url, theme, encoding).await
+}
+
+/// whether a markdown link target points off-site and should be hardened
+/// with `target="_blank" rel="nofollow noreferrer"`. relative links, and
+/// links back to the host we're serving on, are left alone.
+fn is_external_link(url: &str, host: Option<&str>) -> bool {
+    let after_scheme = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"));
 
-    create_file_reply(tools, path, html, true, url)
+    let link_host = match after_scheme {
+        Some(rest) => rest.split(['/', '?', '#']).next().unwrap_or(""),
+        // not an absolute http(s) url, so it's not "external" in the sense we care about
+        None => return false,
+    };
+
+    match host {
+        Some(host) => !link_host.eq_ignore_ascii_case(host),
+        None => true,
+    }
+}
+
+static EXTERNAL_LINK_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r#"(?s)<a\s+href="([^"]*)"([^>]*)>"#).unwrap());
+
+/// adds `target="_blank" rel="nofollow noreferrer"` to every `<a href="...">`
+/// tag in already-rendered markdown html whose `href` is external, per
+/// `is_external_link`; a tag that already sets a `target` is left as it is.
+/// this works on the rendered string rather than the
+/// comrak AST so it never has to rebuild (and thus flatten) the link's
+/// inner markup — nested emphasis, inline code, or an image used as the
+/// link's label all survive untouched.
+fn harden_external_links(html: &str, host: Option<&str>) -> String {
+    EXTERNAL_LINK_RE.replace_all(html, |caps: &regex::Captures| {
+        let href = &caps[1];
+        let attrs = &caps[2];
+        if attrs.contains("target=") || !is_external_link(href, host) {
+            caps[0].to_string()
+        } else {
+            format!(
+                "<a href=\"{}\"{} target=\"_blank\" rel=\"nofollow noreferrer\">",
+                href, attrs
+            )
+        }
+    })
+    .into_owned()
+}
+
+/// collapses insignificant whitespace, strips html comments, and trims
+/// attribute quoting where safe. whitespace inside `<pre>`/`<code>`/
+/// `<script>`/`<style>` blocks is kept as is; comments and attribute quotes
+/// are handled the same inside those blocks as everywhere else.
+fn minify_html(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut chars = html.char_indices().peekable();
+    let mut preserve_depth = 0usize;
+
+    // advances `chars` past every byte up to and including `end_byte`
+    fn skip_to(chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>, end_byte: usize) {
+        while let Some(&(idx, _)) = chars.peek() {
+            if idx > end_byte {
+                break;
+            }
+            chars.next();
+        }
+    }
+
+    // finds the byte offset of the `>` that closes a tag starting at the
+    // front of `s`, skipping over any `>` that appears inside a quoted
+    // attribute value (e.g. `<a title="a>b">`).
+    fn find_tag_end(s: &str) -> Option<usize> {
+        let mut quote = None;
+        for (idx, c) in s.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {},
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '>' => return Some(idx),
+                None => {},
+            }
+        }
+        None
+    }
+
+    while let Some((i, c)) = chars.next() {
+        // strip html comments entirely, even inside preserved blocks
+        if c == '<' && html[i..].starts_with("<!--") {
+            if let Some(comment_end) = html[i..].find("-->") {
+                skip_to(&mut chars, i + comment_end + "-->".len() - 1);
+                continue;
+            }
+        }
+
+        if c == '<' {
+            if let Some(tag_end) = find_tag_end(&html[i..]) {
+                let tag_end_byte = i + tag_end;
+                let tag = &html[i..=tag_end_byte];
+                let lower = tag.to_ascii_lowercase();
+                let is_closing = lower.starts_with("</");
+                let name: String = lower[if is_closing { 2 } else { 1 }..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .collect();
+
+                if name == "pre" || name == "code" || name == "script" || name == "style" {
+                    if is_closing {
+                        preserve_depth = preserve_depth.saturating_sub(1);
+                    } else if !lower.ends_with("/>") {
+                        preserve_depth += 1;
+                    }
+                }
+
+                out.push_str(&trim_attr_quotes(tag));
+                skip_to(&mut chars, tag_end_byte);
+                continue;
+            }
+        }
+
+        if preserve_depth > 0 {
+            out.push(c);
+            continue;
+        }
+
+        if c.is_whitespace() {
+            // collapse a whole run of whitespace (incl. newlines) into one space
+            while matches!(chars.peek(), Some((_, next)) if next.is_whitespace()) {
+                chars.next();
+            }
+            out.push(' ');
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+static ATTR_QUOTE_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r#"="([^"'\s<>`=]+)"([\s>])"#).unwrap());
+
+/// drops quotes around html attribute values that don't contain whitespace
+/// or characters that would make the quotes load-bearing. a value directly
+/// followed by `/>` keeps its quotes, since unquoted it would swallow the `/`.
+fn trim_attr_quotes(tag: &str) -> String {
+    ATTR_QUOTE_RE.replace_all(tag, "=${1}${2}").into_owned()
 }
 
 /// renders the file template, returning a reply or rejection.
-fn create_file_reply(
+async fn create_file_reply(
     tools: Arc<Tools>,
     path: &Path,
     content: String,
     unsafe_content: bool,
     url: &str,
-) -> Result<Html<String>, Rejection> {
-    Context::from_serialize(FileContent {
+    theme: String,
+    encoding: Option<Encoding>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let rendered = Context::from_serialize(FileContent {
         content,
         unsafe_content,
         file_name: path
@@ -352,10 +914,12 @@ fn create_file_reply(
             .map(|b| b.to_string_lossy().to_string())
             .unwrap_or_else(|_| "<unknown>".to_string()),
         raw_url: format!("{}?raw=true", url),
+        theme,
     })
     .and_then(|c| tools.tera.render("file.html", &c))
-    .map(reply::html)
-    .map_err(|_| reject::custom(UltiserveReject::RenderFail))
+    .map_err(|_| reject::custom(UltiserveReject::RenderFail))?;
+
+    html_reply(&tools, rendered, encoding).await
 }
 
 /// a set of tools passed to the request handler
@@ -363,7 +927,9 @@ struct Tools {
     /// template engine
     tera: Tera,
     syntax_set: SyntaxSet,
-    theme_set: ThemeSet,
+    /// precomputed `theme name -> css` stylesheets, one per theme in the
+    /// loaded `ThemeSet`, served under `/_ultiserve/theme/{name}.css`
+    theme_css: HashMap<String, String>,
     /// command line options
     opt: Opt,
 }
@@ -382,12 +948,125 @@ struct IndexContent {
     full_current_dir: String,
     current_dir: String,
     has_parent: bool,
+    theme: String,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct FileEntry {
     name: String,
     is_dir: bool,
+    kind: FileKind,
+}
+
+/// the broad category a directory entry falls into, used by templates to
+/// show a type glyph next to each entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FileKind {
+    Folder,
+    Image,
+    Video,
+    Audio,
+    Archive,
+    Code,
+    Document,
+    Generic,
+}
+
+impl FileKind {
+    /// figures out the kind of a directory entry from its name, mostly by
+    /// extension.
+    fn of(name: &str, is_dir: bool) -> Self {
+        if is_dir {
+            return FileKind::Folder;
+        }
+
+        let ext = Path::new(name.trim_end_matches('/'))
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "svg" | "ico") => {
+                FileKind::Image
+            },
+            Some("mp4" | "mkv" | "webm" | "avi" | "mov" | "flv") => FileKind::Video,
+            Some("mp3" | "wav" | "flac" | "ogg" | "opus" | "m4a") => FileKind::Audio,
+            Some("zip" | "tar" | "gz" | "xz" | "bz2" | "7z" | "rar" | "zst") => FileKind::Archive,
+            Some(
+                "rs" | "js" | "ts" | "py" | "go" | "c" | "cpp" | "h" | "hpp" | "java" | "rb"
+                | "sh" | "json" | "toml" | "yaml" | "yml" | "html" | "css",
+            ) => FileKind::Code,
+            Some("md" | "markdown" | "txt" | "pdf" | "doc" | "docx" | "odt") => {
+                FileKind::Document
+            },
+            _ => FileKind::Generic,
+        }
+    }
+}
+
+/// compares two names in "natural" order: alternating runs of digits and
+/// non-digits, with digit runs compared numerically and text runs compared
+/// case-insensitively. this way `file2` sorts before `file10`.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        let (a_next, b_next) = (a_chars.peek(), b_chars.peek());
+        return match (a_next, b_next) {
+            (None, None) => std::cmp::Ordering::Equal,
+            (None, Some(_)) => std::cmp::Ordering::Less,
+            (Some(_), None) => std::cmp::Ordering::Greater,
+            (Some(ac), Some(bc)) if ac.is_ascii_digit() && bc.is_ascii_digit() => {
+                let a_run = take_digit_run(&mut a_chars);
+                let b_run = take_digit_run(&mut b_chars);
+                // compare numerically, ignoring leading zeros
+                let a_num: u128 = a_run.parse().unwrap_or(0);
+                let b_num: u128 = b_run.parse().unwrap_or(0);
+                match a_num.cmp(&b_num) {
+                    std::cmp::Ordering::Equal => continue,
+                    ord => ord,
+                }
+            },
+            _ => {
+                let a_run = take_text_run(&mut a_chars);
+                let b_run = take_text_run(&mut b_chars);
+                match a_run.to_lowercase().cmp(&b_run.to_lowercase()) {
+                    std::cmp::Ordering::Equal => continue,
+                    ord => ord,
+                }
+            },
+        };
+    }
+}
+
+/// consumes and returns a contiguous run of ascii digits from the front of
+/// the iterator.
+fn take_digit_run(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut run = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        run.push(c);
+        chars.next();
+    }
+    run
+}
+
+/// consumes and returns a contiguous run of non-digit characters from the
+/// front of the iterator.
+fn take_text_run(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut run = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() {
+            break;
+        }
+        run.push(c);
+        chars.next();
+    }
+    run
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -396,4 +1075,5 @@ struct FileContent {
     unsafe_content: bool,
     file_name: String,
     raw_url: String,
+    theme: String,
 }